@@ -2,9 +2,24 @@ use async_channel::unbounded;
 use async_channel::{Receiver, Sender};
 use thiserror::Error;
 
+use async_io::Timer;
+use futures_lite::future::FutureExt;
+
 use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// A health-check run on an object as it is returned to the pool. Returning
+/// `false` means the object is broken and should be discarded rather than
+/// handed back out.
+pub type RecycleFn<T> = Arc<dyn Fn(&mut T) -> bool + Send + Sync>;
+
+/// A normalization step run on an object just before it re-enters the pool,
+/// e.g. `|buf| buf.clear()`. Unlike [`RecycleFn`] it never discards the object,
+/// it only resets its state so a pulled object is always ready to use.
+pub type ResetFn<T> = Arc<dyn Fn(&mut T) + Send + Sync>;
 
 #[derive(Error, std::fmt::Debug)]
 pub enum PoolError {
@@ -12,33 +27,132 @@ pub enum PoolError {
     AttachError,
     #[error("No buffers available in pool")]
     NoBuffersAvailable,
+    #[error("Timed out waiting for a buffer")]
+    Timeout,
+    #[error("Pool has been closed")]
+    Closed,
 }
 
-#[derive(Clone)]
-pub struct Pool<F, T> {
-    object_bucket: Receiver<T>,
-    object_return: Sender<T>,
+/// Builder for [`Pool`]. Collects the optional knobs (max size, recycle hook,
+/// …) so they can be combined without a constructor per combination, then
+/// materializes the pool with [`PoolBuilder::build`].
+pub struct PoolBuilder<F, T> {
+    initial_capacity: usize,
+    max_size: usize,
     extend_fn: F,
+    recycle_fn: Option<RecycleFn<T>>,
+    reset_fn: Option<ResetFn<T>>,
 }
 
-impl<F, T: std::marker::Send> Pool<Arc<F>, T>
+impl<F, T: std::marker::Send> PoolBuilder<Arc<F>, T>
 where
     F: Fn() -> T + std::marker::Send + std::marker::Sync + 'static + ?Sized,
 {
+    /// Start a builder for a pool whose objects are produced by `init`.
     #[inline]
-    pub fn new(initial_capacity: usize, init: Arc<F>) -> Self {
+    pub fn new(init: Arc<F>) -> Self {
+        PoolBuilder {
+            initial_capacity: 0,
+            max_size: usize::MAX,
+            extend_fn: init,
+            recycle_fn: None,
+            reset_fn: None,
+        }
+    }
+
+    /// Number of objects to eagerly allocate up front. Defaults to `0`.
+    #[inline]
+    pub fn initial_capacity(mut self, initial_capacity: usize) -> Self {
+        self.initial_capacity = initial_capacity;
+        self
+    }
+
+    /// Hard ceiling on the number of live objects. Defaults to unbounded.
+    #[inline]
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Health-check run on every object as it is returned. See [`RecycleFn`].
+    #[inline]
+    pub fn recycle(mut self, recycle_fn: RecycleFn<T>) -> Self {
+        self.recycle_fn = Some(recycle_fn);
+        self
+    }
+
+    /// Normalization step run on every object just before it re-enters the
+    /// pool, e.g. `|buf| buf.clear()`. See [`ResetFn`].
+    #[inline]
+    pub fn reset(mut self, reset_fn: ResetFn<T>) -> Self {
+        self.reset_fn = Some(reset_fn);
+        self
+    }
+
+    /// Materialize the pool, eagerly allocating `initial_capacity` objects.
+    /// The eager fill is clamped to `max_size` so it can never start the pool
+    /// over its own ceiling.
+    #[inline]
+    pub fn build(self) -> Pool<Arc<F>, T> {
         let (s, r) = unbounded();
 
+        let initial_capacity = self.initial_capacity.min(self.max_size);
         for _ in 0..initial_capacity {
-            s.try_send(init()).expect("Pool is closed");
+            s.try_send((self.extend_fn)()).expect("Pool is closed");
         }
 
         Pool {
             object_bucket: r,
             object_return: s,
-            extend_fn: init,
+            extend_fn: self.extend_fn,
+            recycle_fn: self.recycle_fn,
+            reset_fn: self.reset_fn,
+            size: Arc::new(AtomicUsize::new(initial_capacity)),
+            max_size: self.max_size,
+            closed: Arc::new(AtomicBool::new(false)),
         }
     }
+}
+
+/// A point-in-time snapshot of a [`Pool`]'s occupancy, useful for wiring the
+/// pool into metrics/telemetry or detecting exhaustion and leaks.
+#[derive(Clone, Copy, std::fmt::Debug, PartialEq, Eq)]
+pub struct Status {
+    /// Total number of live objects (idle plus checked out).
+    pub size: usize,
+    /// Objects currently idle in the pool and ready to be pulled.
+    pub available: usize,
+    /// Objects currently checked out (`size - available`).
+    pub in_use: usize,
+    /// Hard ceiling on the number of live objects. `usize::MAX` is unbounded.
+    pub max_size: usize,
+}
+
+#[derive(Clone)]
+pub struct Pool<F, T> {
+    object_bucket: Receiver<T>,
+    object_return: Sender<T>,
+    extend_fn: F,
+    recycle_fn: Option<RecycleFn<T>>,
+    reset_fn: Option<ResetFn<T>>,
+    /// Total number of live objects (idle plus checked out).
+    size: Arc<AtomicUsize>,
+    /// Hard ceiling on the number of live objects. `usize::MAX` is unbounded.
+    max_size: usize,
+    /// Set once the pool has been closed via [`Pool::close`]/[`Pool::drain`].
+    closed: Arc<AtomicBool>,
+}
+
+impl<F, T: std::marker::Send> Pool<Arc<F>, T>
+where
+    F: Fn() -> T + std::marker::Send + std::marker::Sync + 'static + ?Sized,
+{
+    #[inline]
+    pub fn new(initial_capacity: usize, init: Arc<F>) -> Self {
+        PoolBuilder::new(init)
+            .initial_capacity(initial_capacity)
+            .build()
+    }
 
     #[inline]
     pub fn len(&self) -> usize {
@@ -50,61 +164,226 @@ where
         self.object_bucket.is_empty()
     }
 
+    /// Snapshot the pool's current occupancy. `in_use` is derived as
+    /// `size - available`; because the two counters are read independently it
+    /// is saturated at zero rather than underflowing.
+    #[inline]
+    pub fn status(&self) -> Status {
+        let size = self.size.load(Ordering::SeqCst);
+        let available = self.object_bucket.len();
+        Status {
+            size,
+            available,
+            in_use: size.saturating_sub(available),
+            max_size: self.max_size,
+        }
+    }
+
     #[inline]
-    pub async fn pull(&self) -> Option<Reusable<T>> {
+    fn wrap(&self, data: T) -> Reusable<F, T> {
+        Reusable::new(
+            self.object_return.clone(),
+            data,
+            self.extend_fn.clone(),
+            self.recycle_fn.clone(),
+            self.reset_fn.clone(),
+            self.size.clone(),
+            self.closed.clone(),
+        )
+    }
+
+    /// Signal that the pool is shutting down. After this returns, `try_pull`
+    /// and `pull_timeout` fail with [`PoolError::Closed`], `pull` returns
+    /// `None` (it does not report `Closed`), and returned objects are dropped
+    /// rather than reclaimed.
+    #[inline]
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.object_return.close();
+    }
+
+    /// Whether the pool has been closed.
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Close the pool and collect every object currently idle in it so the
+    /// caller can perform explicit cleanup (e.g. flushing buffers to disk).
+    #[inline]
+    pub fn drain(self) -> Vec<T> {
+        self.close();
+        let mut drained = Vec::new();
+        while let Ok(obj) = self.object_bucket.try_recv() {
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            drained.push(obj);
+        }
+        drained
+    }
+
+    #[inline]
+    pub async fn pull(&self) -> Option<Reusable<F, T>> {
+        if self.is_closed() {
+            return None;
+        }
         self.object_bucket
             .recv()
             .await
             .ok()
-            .map(|data| Reusable::new(self.object_return.clone(), data))
+            .map(|data| self.wrap(data))
     }
 
+    /// Like [`Pool::pull`] but gives up with [`PoolError::Timeout`] once `dur`
+    /// elapses, so callers under load don't block forever.
     #[inline]
-    pub fn try_pull(&self) -> Result<Reusable<T>, PoolError> {
-        self.object_bucket
-            .try_recv()
-            .map(|data| Reusable::new(self.object_return.clone(), data))
-            .map_err(|_| /*TODO handle the real errors*/ PoolError::NoBuffersAvailable)
+    pub async fn pull_timeout(&self, dur: Duration) -> Result<Reusable<F, T>, PoolError> {
+        if self.is_closed() {
+            return Err(PoolError::Closed);
+        }
+        let acquire = async { self.pull().await.ok_or(PoolError::NoBuffersAvailable) };
+        let timeout = async {
+            Timer::after(dur).await;
+            Err(PoolError::Timeout)
+        };
+        acquire.or(timeout).await
     }
 
     #[inline]
-    pub async fn attach(&self, t: T) -> Result<(), PoolError> {
-        self.object_return
-            .send(t)
-            .await
-            .map_err(|_| PoolError::AttachError)
+    pub fn try_pull(&self) -> Result<Reusable<F, T>, PoolError> {
+        if self.is_closed() {
+            return Err(PoolError::Closed);
+        }
+        match self.object_bucket.try_recv() {
+            Ok(data) => Ok(self.wrap(data)),
+            Err(_) => self.grow_one(),
+        }
     }
 
+    /// Grow the pool on demand when the bucket is empty, respecting `max_size`.
     #[inline]
-    pub fn try_attach(&self, t: T) -> Result<(), PoolError> {
-        self.object_return
-            .try_send(t)
-            .map_err(|_| PoolError::AttachError)
+    fn grow_one(&self) -> Result<Reusable<F, T>, PoolError> {
+        if self.reserve() {
+            Ok(self.wrap((self.extend_fn)()))
+        } else {
+            Err(PoolError::NoBuffersAvailable)
+        }
+    }
+
+    /// Reserve a slot for one more live object, respecting `max_size`. Returns
+    /// `false` (and leaves the count unchanged) if the pool is already full.
+    #[inline]
+    fn reserve(&self) -> bool {
+        let prev = self.size.fetch_add(1, Ordering::SeqCst);
+        if prev < self.max_size {
+            true
+        } else {
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            false
+        }
+    }
+
+    #[inline]
+    pub async fn attach(&self, mut t: T) -> Result<(), PoolError> {
+        if let Some(recycle) = &self.recycle_fn {
+            if !recycle(&mut t) {
+                // Discard the broken object and backfill a fresh one.
+                t = (self.extend_fn)();
+            }
+        }
+        if let Some(reset) = &self.reset_fn {
+            reset(&mut t);
+        }
+        self.object_return.send(t).await.map_err(|_| {
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            PoolError::AttachError
+        })
+    }
+
+    #[inline]
+    pub fn try_attach(&self, mut t: T) -> Result<(), PoolError> {
+        if let Some(recycle) = &self.recycle_fn {
+            if !recycle(&mut t) {
+                t = (self.extend_fn)();
+            }
+        }
+        if let Some(reset) = &self.reset_fn {
+            reset(&mut t);
+        }
+        self.object_return.try_send(t).map_err(|_| {
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            PoolError::AttachError
+        })
     }
 
     #[inline]
     pub fn expand(&mut self) -> Result<(), PoolError> {
-        self.try_attach((self.extend_fn)())
+        if !self.reserve() {
+            return Err(PoolError::AttachError);
+        }
+        self.object_return
+            .try_send((self.extend_fn)())
+            .map_err(|_| {
+                self.size.fetch_sub(1, Ordering::SeqCst);
+                PoolError::AttachError
+            })
     }
 }
 
-pub struct Reusable<T> {
+pub struct Reusable<F, T>
+where
+    F: Fn() -> T + ?Sized,
+{
     pool: Sender<T>,
     data: ManuallyDrop<T>,
+    extend_fn: Arc<F>,
+    recycle_fn: Option<RecycleFn<T>>,
+    reset_fn: Option<ResetFn<T>>,
+    size: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
 }
 
-impl<'a, T> Reusable<T> {
+impl<F, T> Reusable<F, T>
+where
+    F: Fn() -> T + ?Sized,
+{
     #[inline]
-    pub fn new(pool: Sender<T>, t: T) -> Self {
+    pub fn new(
+        pool: Sender<T>,
+        t: T,
+        extend_fn: Arc<F>,
+        recycle_fn: Option<RecycleFn<T>>,
+        reset_fn: Option<ResetFn<T>>,
+        size: Arc<AtomicUsize>,
+        closed: Arc<AtomicBool>,
+    ) -> Self {
         Self {
             pool,
             data: ManuallyDrop::new(t),
+            extend_fn,
+            recycle_fn,
+            reset_fn,
+            size,
+            closed,
         }
     }
 
     #[inline]
-    pub fn detach(mut self) -> (Sender<T>, T) {
-        (self.pool.clone(), unsafe { self.take() })
+    pub fn detach(self) -> (Sender<T>, T) {
+        // Move `self` into a `ManuallyDrop` so `Reusable::drop` never runs — it
+        // would otherwise take `data` a second time and double-free it. Each
+        // field is moved out exactly once: `pool` and `data` are returned, the
+        // remaining handles are dropped in place.
+        let mut this = ManuallyDrop::new(self);
+        let data = unsafe { ManuallyDrop::take(&mut this.data) };
+        let pool = unsafe { std::ptr::read(&this.pool) };
+        unsafe {
+            std::ptr::drop_in_place(&mut this.extend_fn);
+            std::ptr::drop_in_place(&mut this.recycle_fn);
+            std::ptr::drop_in_place(&mut this.reset_fn);
+            std::ptr::drop_in_place(&mut this.size);
+            std::ptr::drop_in_place(&mut this.closed);
+        }
+        (pool, data)
     }
 
     unsafe fn take(&mut self) -> T {
@@ -112,7 +391,10 @@ impl<'a, T> Reusable<T> {
     }
 }
 
-impl<'a, T> Deref for Reusable<T> {
+impl<F, T> Deref for Reusable<F, T>
+where
+    F: Fn() -> T + ?Sized,
+{
     type Target = T;
 
     #[inline]
@@ -121,21 +403,198 @@ impl<'a, T> Deref for Reusable<T> {
     }
 }
 
-impl<'a, T> DerefMut for Reusable<T> {
+impl<F, T> DerefMut for Reusable<F, T>
+where
+    F: Fn() -> T + ?Sized,
+{
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.data
     }
 }
 
-impl<'a, T> Drop for Reusable<T> {
+impl<F, T> Drop for Reusable<F, T>
+where
+    F: Fn() -> T + ?Sized,
+{
     #[inline]
     fn drop(&mut self) {
-        let obj = unsafe { self.take() };
-        // If we can't put it back on the pool drop it
+        let mut obj = unsafe { self.take() };
+        // A closed pool no longer reclaims objects: drop it and shrink the
+        // live count instead of trying to return it to a dead channel.
+        if self.closed.load(Ordering::SeqCst) {
+            drop(obj);
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        // Health-check the object before it re-enters the pool. If it is broken
+        // discard it and backfill a freshly-built one so capacity is preserved.
+        if let Some(recycle) = &self.recycle_fn {
+            if !recycle(&mut obj) {
+                drop(obj);
+                // Backfill a fresh object so capacity is preserved, normalizing
+                // it the same way the other return paths do; if the pool is gone
+                // the count shrinks instead.
+                let mut fresh = (self.extend_fn)();
+                if let Some(reset) = &self.reset_fn {
+                    reset(&mut fresh);
+                }
+                if self.pool.try_send(fresh).is_err() {
+                    self.size.fetch_sub(1, Ordering::SeqCst);
+                }
+                return;
+            }
+        }
+        // Normalize the object's state before it re-enters the pool so the
+        // next caller always gets a clean object.
+        if let Some(reset) = &self.reset_fn {
+            reset(&mut obj);
+        }
+        // If we can't put it back on the pool drop it, and shrink the live
+        // count since the object is genuinely gone.
         match self.pool.try_send(obj) {
             Ok(_) => {}
-            Err(e) => drop(e),
+            Err(e) => {
+                drop(e);
+                self.size.fetch_sub(1, Ordering::SeqCst);
+            }
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+
+    fn vec_init() -> Arc<dyn Fn() -> Vec<u8> + Send + Sync> {
+        Arc::new(Vec::new)
+    }
+
+    #[test]
+    fn status_accounts_for_pull_attach_and_drop() {
+        let pool = Pool::new(2, vec_init());
+
+        let status = pool.status();
+        assert_eq!(status.size, 2);
+        assert_eq!(status.available, 2);
+        assert_eq!(status.in_use, 0);
+
+        let obj = pool.try_pull().unwrap();
+        let status = pool.status();
+        assert_eq!(status.size, 2);
+        assert_eq!(status.available, 1);
+        assert_eq!(status.in_use, 1);
+
+        drop(obj);
+        assert_eq!(pool.status().available, 2);
+        assert_eq!(pool.status().in_use, 0);
+
+        // Detaching takes an object out of circulation without changing the
+        // live count, and attaching it back is a pure return path: the size is
+        // unchanged and the object becomes available again.
+        let (_sender, buf) = pool.try_pull().unwrap().detach();
+        let status = pool.status();
+        assert_eq!(status.size, 2);
+        assert_eq!(status.available, 1);
+
+        block_on(pool.attach(buf)).unwrap();
+        let status = pool.status();
+        assert_eq!(status.size, 2);
+        assert_eq!(status.available, 2);
+    }
+
+    #[test]
+    fn detach_yields_object_without_double_free() {
+        let pool = Pool::new(1, vec_init());
+        let (_sender, mut buf) = pool.try_pull().unwrap().detach();
+        // The detached object is ours now; using it must not trip a double free
+        // when the originating `Reusable` would otherwise have been dropped.
+        buf.extend_from_slice(b"owned");
+        assert_eq!(buf, b"owned");
+        // It is still counted as live until it is handed back.
+        assert_eq!(pool.status().size, 1);
+        assert_eq!(pool.status().available, 0);
+    }
+
+    #[test]
+    fn max_size_rejects_further_growth() {
+        let pool = PoolBuilder::new(vec_init()).max_size(1).build();
+
+        // First pull grows the pool on demand up to the ceiling.
+        let _obj = pool.try_pull().unwrap();
+        assert_eq!(pool.status().size, 1);
+
+        // The pool is full and empty, so the next pull is refused.
+        assert!(matches!(
+            pool.try_pull(),
+            Err(PoolError::NoBuffersAvailable)
+        ));
+        assert_eq!(pool.status().size, 1);
+    }
+
+    #[test]
+    fn pull_timeout_fires_when_empty() {
+        let pool = Pool::new(0, vec_init());
+        let result = block_on(pool.pull_timeout(Duration::from_millis(20)));
+        assert!(matches!(result, Err(PoolError::Timeout)));
+    }
+
+    #[test]
+    fn recycle_discards_and_backfills_broken_objects() {
+        let built = Arc::new(AtomicUsize::new(0));
+        let counter = built.clone();
+        let init: Arc<dyn Fn() -> Vec<u8> + Send + Sync> = Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            vec![0u8]
+        });
+        let recycle: RecycleFn<Vec<u8>> =
+            Arc::new(|buf: &mut Vec<u8>| buf.first() != Some(&0xFF));
+
+        let pool = PoolBuilder::new(init)
+            .initial_capacity(1)
+            .recycle(recycle)
+            .build();
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+
+        // Poison the object so the recycle check rejects it on return.
+        let mut obj = pool.try_pull().unwrap();
+        obj[0] = 0xFF;
+        drop(obj);
+
+        // The broken object was discarded and a fresh one built in its place,
+        // so capacity is preserved and the next pull sees a clean object.
+        assert_eq!(built.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.status().size, 1);
+        let obj = pool.try_pull().unwrap();
+        assert_eq!(obj[0], 0);
+    }
+
+    #[test]
+    fn reset_clears_objects_before_reuse() {
+        let reset: ResetFn<Vec<u8>> = Arc::new(|buf: &mut Vec<u8>| buf.clear());
+        let pool = PoolBuilder::new(vec_init())
+            .initial_capacity(1)
+            .reset(reset)
+            .build();
+
+        let mut obj = pool.try_pull().unwrap();
+        obj.extend_from_slice(b"dirty");
+        drop(obj);
+
+        let obj = pool.try_pull().unwrap();
+        assert!(obj.is_empty());
+    }
+
+    #[test]
+    fn drain_after_close_collects_and_rejects() {
+        let pool = Pool::new(3, vec_init());
+
+        let drained = pool.clone().drain();
+        assert_eq!(drained.len(), 3);
+
+        assert!(pool.is_closed());
+        assert_eq!(pool.status().size, 0);
+        assert!(matches!(pool.try_pull(), Err(PoolError::Closed)));
+    }
+}